@@ -1,12 +1,13 @@
+use std::collections::HashSet;
 use std::convert::Into;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use ekiden_common::bytes::B256;
 use ekiden_common::error::Error;
-use ekiden_common::futures::{future, BoxFuture, Future, Stream};
+use ekiden_common::futures::{future, BoxFuture, BoxStream, Future, Stream};
 use ekiden_scheduler_api as api;
 use grpcio::{RpcContext, RpcStatus, ServerStreamingSink, UnarySink, WriteFlags};
-use grpcio::RpcStatusCode::{Internal, InvalidArgument};
+use grpcio::RpcStatusCode::{Internal, InvalidArgument, NotFound, OutOfRange, Unavailable};
 use protobuf::RepeatedField;
 
 use super::backend::{Committee, Scheduler};
@@ -68,19 +69,176 @@ impl api::Scheduler for SchedulerService {
         }).map_err(|_e| ()));
     }
 
+    fn get_committees_at(
+        &self,
+        ctx: RpcContext,
+        req: api::CommitteeRequest,
+        sink: UnarySink<api::CommitteeResponse>,
+    ) {
+        let f = move || -> Result<BoxFuture<Vec<Committee>>, Error> {
+            let contract_id = B256::from_slice(req.get_contract_id());
+            // The backend checks retention synchronously, so a requested epoch that
+            // predates retained history surfaces here rather than as a future error.
+            self.inner.get_committees_at(contract_id, req.get_epoch())
+        };
+        let f = match f() {
+            Ok(f) => f.then(|res| match res {
+                Ok(committees) => {
+                    let mut resp = api::CommitteeResponse::new();
+                    let mut members = Vec::new();
+                    for member in committees.iter() {
+                        members.push(member.to_owned().into());
+                    }
+                    resp.set_committee(RepeatedField::from_vec(members));
+                    Ok(resp)
+                }
+                Err(e) => Err(e),
+            }),
+            Err(e) => {
+                ctx.spawn(invalid!(sink, NotFound, e).map_err(|_e| ()));
+                return;
+            }
+        };
+        ctx.spawn(f.then(move |r| match r {
+            Ok(ret) => sink.success(ret),
+            // Anything that fails after retention has already been confirmed is a
+            // genuine backend fault, same as `get_committees`.
+            Err(e) => invalid!(sink, Internal, e),
+        }).map_err(|_e| ()));
+    }
+
     fn watch_committees(
         &self,
         ctx: RpcContext,
-        _req: api::WatchRequest,
+        req: api::WatchRequest,
         sink: ServerStreamingSink<api::WatchResponse>,
     ) {
-        let f = self.inner
-            .watch_committees()
-            .map(|res| -> (api::WatchResponse, WriteFlags) {
-                let mut r = api::WatchResponse::new();
-                r.set_committee(res.into());
-                (r, WriteFlags::default())
-            });
-        ctx.spawn(f.forward(sink).then(|_f| future::ok(())));
+        let contract_ids = {
+            let ids = req.get_contract_id();
+            if ids.is_empty() {
+                None
+            } else {
+                Some(ids.iter().map(|id| B256::from_slice(id)).collect())
+            }
+        };
+        let f = move || -> Result<BoxStream<Committee>, Error> {
+            let live = self.inner.watch_committees();
+            if req.has_from_epoch() {
+                // Catch the subscriber up on everything scheduled since the epoch they
+                // last saw, then transparently fall through to the live tail.
+                let catch_up = self.inner.get_committees_since(req.get_from_epoch())?;
+                Ok(replay_committees(catch_up, live))
+            } else {
+                Ok(live)
+            }
+        };
+        let stream = match f() {
+            Ok(stream) => stream,
+            Err(e) => {
+                ctx.spawn(invalid!(sink, OutOfRange, e).map_err(|_e| ()));
+                return;
+            }
+        };
+        let stream = filter_committees(stream, contract_ids);
+        // Drive the stream by hand instead of `.forward(sink)`: `forward` drops the
+        // sink the moment the stream errors, which would leave us unable to close
+        // the RPC with a meaningful status and collapse every failure to grpcio's
+        // default. `loop_fn` keeps the sink alive across iterations so the stream
+        // ending and the stream erroring can both fail it explicitly. A failed
+        // write consumes the sink, so that case just ends the loop with no status.
+        let driver = future::loop_fn((stream, sink), |(stream, sink)| {
+            stream.into_future().then(|res| match res {
+                Ok((Some(committee), stream)) => {
+                    let mut r = api::WatchResponse::new();
+                    r.set_committee(committee.into());
+                    future::Either::A(sink.send((r, WriteFlags::default())).then(
+                        move |res| match res {
+                            Ok(sink) => Ok(future::Loop::Continue((stream, sink))),
+                            Err(_e) => Ok(future::Loop::Break(())),
+                        },
+                    ))
+                }
+                Ok((None, _stream)) => {
+                    // `watch_committees` is meant to run for the life of the
+                    // subscription, so the backend stream completing on its own
+                    // (rather than this future being dropped when the client
+                    // disconnects) means the scheduler backend went away, e.g.
+                    // during a reconfiguration.
+                    let e = Error::new("scheduler backend stream terminated");
+                    future::Either::B(
+                        invalid!(sink, Unavailable, e).then(|_e| Ok(future::Loop::Break(()))),
+                    )
+                }
+                Err((e, _stream)) => {
+                    future::Either::B(invalid!(sink, Internal, e).then(|_e| Ok(future::Loop::Break(()))))
+                }
+            })
+        });
+        ctx.spawn(driver);
+    }
+}
+
+/// Narrows a stream of committee updates to only those for the given contracts,
+/// the same way a subscription against an event filter only yields matching log
+/// entries. A `None` (empty) filter preserves the firehose behavior of forwarding
+/// every update. Note that a committee for a contract outside the filter is
+/// dropped even when it replaces one the subscriber previously received; clients
+/// that shrink their filter are responsible for reconciling membership changes on
+/// the contracts they stop watching.
+fn filter_committees<S>(stream: S, contract_ids: Option<HashSet<B256>>) -> BoxStream<Committee>
+where
+    S: Stream<Item = Committee, Error = Error> + Send + 'static,
+{
+    match contract_ids {
+        Some(ids) => Box::new(stream.filter(move |committee| ids.contains(&committee.contract_id()))),
+        None => Box::new(stream),
     }
 }
+
+/// Chains a backend replay of committees since the requested epoch ahead of the live
+/// `watch_committees()` tail, so a client resuming after a dropped stream catches up
+/// instead of silently missing rotations that happened during the gap. The replay
+/// tail and the live head can overlap by a committee or two (they are sourced a
+/// moment apart), so entries are de-duplicated by `(contract_id, epoch)` while that
+/// overlap is still possible.
+///
+/// The de-dup set only needs to live as long as the overlap itself: it is filled in
+/// while draining the (bounded) replay tail and is dropped as soon as the live
+/// stream has moved past the highest epoch the replay covered, so a long-lived
+/// subscription doesn't carry it for the rest of the connection's life.
+fn replay_committees(catch_up: BoxStream<Committee>, live: BoxStream<Committee>) -> BoxStream<Committee> {
+    let overlap = Arc::new(Mutex::new(Some(HashSet::new())));
+    let max_epoch = Arc::new(Mutex::new(None));
+
+    let seen = overlap.clone();
+    let high_water = max_epoch.clone();
+    let catch_up = catch_up.map(move |committee| {
+        if let Some(ref mut seen) = *seen.lock().unwrap() {
+            seen.insert((committee.contract_id(), committee.epoch()));
+        }
+        let mut high_water = high_water.lock().unwrap();
+        *high_water = Some(
+            high_water
+                .map_or(committee.epoch(), |epoch| epoch.max(committee.epoch())),
+        );
+        committee
+    });
+
+    let live = live.filter(move |committee| {
+        let mut seen = overlap.lock().unwrap();
+        let keep = match *seen {
+            Some(ref seen) => !seen.contains(&(committee.contract_id(), committee.epoch())),
+            None => true,
+        };
+        let past_replay = max_epoch
+            .lock()
+            .unwrap()
+            .map_or(false, |epoch| committee.epoch() > epoch);
+        if past_replay {
+            *seen = None;
+        }
+        keep
+    });
+
+    Box::new(catch_up.chain(live))
+}